@@ -1,49 +1,171 @@
 /// File format for Dense Tick Format (DTF)
 /// File Spec:
 /// Offset 00: ([u8; 5]) magic value 0x4454469001
-/// Offset 05: ([u8; 9]) Symbol
-/// Offset 14: (u64) number of records
-/// Offset 21: (u32) max ts
+/// Offset 05: ([u8; 4]) CR LF Ctrl-Z LF - catches truncated 7-bit
+///            transfers and CR/LF-mangled copies before they're decoded.
+///            Files written before this check existed don't have it;
+///            `check_signature` falls back to the pre-tail layout below
+///            when these 4 bytes aren't the expected sequence.
+/// Offset 09: (u8) format version - see FORMAT_VERSION_* below
+/// Offset 10: ([u8; 9]) Symbol
+/// Offset 19: (u64) number of records
+/// Offset 27: (u32) max ts
 /// Offset 80: -- records - see below --
+///
+/// Pre-tail layout (no bytes 05-08 above): format version at offset 05,
+/// symbol at offset 06, number of records at offset 15, max ts at offset 23.
 /// Record Spec:
 /// Offset 81: bool for is_snapshot
 /// 1. if is snapshot
 ///        4 bytes (u32): reference ts
 ///        2 bytes (u16): reference seq
 ///        2 bytes (u16): how many records between this snapshot and the next snapshot
-///        
-/// 2. if is record
+///        from FORMAT_VERSION_VARINT onward, also:
+///        4 bytes (u32): max ts among this batch's records
+///        4 bytes (u32): byte length of the records section that follows
+///                       (lets a range query seek past the whole batch)
+///
+/// 2. if is record, version FORMAT_VERSION_FIXED (legacy)
 ///        dts (u16): $ts - reference ts$, 2^16 = 65536 - ~65 seconds
 ///        dseq (u8) $seq - reference seq$ , 2^8 = 256
 ///        is_trade: (u8):
 ///        is_bid: (u8)
 ///        price: (f32)
 ///        size: (f32)
+/// 3. if is record, version FORMAT_VERSION_VARINT (current)
+///        dts (varint): $ts - reference ts$, unsigned LEB128
+///        dseq (varint): $seq - reference seq$, unsigned LEB128
+///        is_trade: (u8):
+///        is_bid: (u8)
+///        price: (f32)
+///        size: (f32)
 
 use conf;
 use db;
+use zstd;
 
 use std::str;
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::error;
+use std::thread;
+use std::sync::mpsc::{sync_channel, SyncSender};
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
 use std::io::{
     Write,
     Read,
     Seek,
+    Cursor,
     BufWriter,
     BufReader,
     SeekFrom
 };
 
 static MAGIC_VALUE : &[u8] = &[0x44, 0x54, 0x46, 0x90, 0x01]; // DTF9001
+// CR, LF, Ctrl-Z, LF - the same PNG-style trick for catching files mangled
+// by a 7-bit transfer or a CRLF/LF line-ending rewrite before they're decoded
+static SIGNATURE_TAIL : &[u8] = &[0x0D, 0x0A, 0x1A, 0x0A];
 const SYMBOL_LEN : usize = 9;
-static SYMBOL_OFFSET : u64 = 5;
-static LEN_OFFSET : u64 = 14;
-static MAX_TS_OFFSET : u64 = 22;
+// Offsets below are relative to a file *without* SIGNATURE_TAIL, i.e. the
+// layout written before this check was added. Files that do carry the tail
+// (anything written by this crate from here on) have each of the four
+// header fields below shifted right by SIGNATURE_TAIL.len() - see
+// `check_signature`. MAIN_OFFSET is the same in both layouts.
+static FORMAT_VERSION_OFFSET : u64 = 5;
+static SYMBOL_OFFSET : u64 = 6;
+static LEN_OFFSET : u64 = 15;
+static MAX_TS_OFFSET : u64 = 23;
 static MAIN_OFFSET : u64 = 80; // main section start at 80
 static ITEM_OFFSET : u64 = 13; // each item has 13 bytes
 
+/// Errors from validating or reading a DTF file's header.
+#[derive(Debug)]
+pub enum FileError {
+    /// The magic/signature bytes didn't match - not a DTF file, or the
+    /// file was mangled (e.g. by a text-mode transfer) in a way that
+    /// corrupted its header.
+    InvalidSignature,
+    Io(io::Error),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileError::InvalidSignature => write!(f, "invalid DTF file signature"),
+            FileError::Io(ref e) => write!(f, "I/O error reading DTF file: {}", e),
+        }
+    }
+}
+
+impl error::Error for FileError {
+    fn description(&self) -> &str {
+        match *self {
+            FileError::InvalidSignature => "invalid DTF file signature",
+            FileError::Io(_) => "I/O error reading DTF file",
+        }
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> FileError {
+        FileError::Io(e)
+    }
+}
+
+// fixed-width u16 dts / u8 dseq, capped at 65535/255 per reference batch
+const FORMAT_VERSION_FIXED : u8 = 1;
+// varint-encoded dts / dseq, unbounded per reference batch
+const FORMAT_VERSION_VARINT : u8 = 2;
+// varint-encoded dts / dseq, records section of each batch zstd-compressed
+const FORMAT_VERSION_COMPRESSED : u8 = 3;
+const CURRENT_FORMAT_VERSION : u8 = FORMAT_VERSION_VARINT;
+
+/// Controls how `encode_opts` writes the records section of a batch.
+/// `compress_lvl <= 0` leaves batches uncompressed (the default);
+/// anything higher zstd-compresses each batch's record bytes before
+/// they hit disk, at that compression level.
+pub struct WriterOpts {
+    pub compress_lvl : i32,
+}
+
+impl Default for WriterOpts {
+    fn default() -> WriterOpts {
+        WriterOpts { compress_lvl: 0 }
+    }
+}
+
+/// Writes an unsigned LEB128 varint: 7 bits per byte, low-to-high, with
+/// the high bit set on every byte but the last.
+fn write_varint(buf : &mut Vec<u8>, mut val : u64) {
+    loop {
+        if val < 0x80 {
+            buf.push(val as u8);
+            break;
+        }
+        buf.push((val as u8 & 0x7f) | 0x80);
+        val >>= 7;
+    }
+}
+
+/// Reads an unsigned LEB128 varint written by `write_varint`.
+fn read_varint<R: Read>(rdr : &mut R) -> u64 {
+    let mut result : u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = rdr.read_u8().expect("varint byte");
+        result |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    result
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Update {
     pub ts: u32,
@@ -58,8 +180,8 @@ impl Update {
 
     fn serialize(&self, ref_ts : u32, ref_seq : u16) -> Vec<u8> {
         let mut buf : Vec<u8> = Vec::new();
-        let _ = buf.write_u16::<BigEndian>((self.ts - ref_ts) as u16);
-        let _ = buf.write_u8((self.seq - ref_seq) as u8);
+        write_varint(&mut buf, (self.ts - ref_ts) as u64);
+        write_varint(&mut buf, (self.seq - ref_seq) as u64);
         let _ = buf.write_u8(self.is_trade as u8);
         let _ = buf.write_u8(self.is_bid as u8);
         let _ = buf.write_f32::<BigEndian>(self.price);
@@ -105,18 +227,20 @@ fn file_writer(fname : &str) -> BufWriter<File> {
     wtr
 }
 
-fn write_magic_value(wtr: &mut BufWriter<File>) {
+fn write_magic_value<W: Write>(wtr: &mut W, version : u8) {
     let _ = wtr.write(MAGIC_VALUE);
+    let _ = wtr.write(SIGNATURE_TAIL);
+    let _ = wtr.write_u8(version);
 }
 
-fn write_symbol(wtr: &mut BufWriter<File>, symbol : &str) {
+fn write_symbol<W: Write>(wtr: &mut W, symbol : &str) {
     assert!(symbol.len() <= SYMBOL_LEN);
     let padded_symbol = format!("{:width$}", symbol, width = SYMBOL_LEN); // right pad w/ space
     assert_eq!(padded_symbol.len(), SYMBOL_LEN);
     let _ = wtr.write(padded_symbol.as_bytes());
 }
 
-fn write_metadata(wtr: &mut BufWriter<File>, ups : &[Update]) {
+fn write_metadata<W: Write>(wtr: &mut W, ups : &[Update]) {
     // number of records
     wtr.write_u64::<BigEndian>(ups.len() as u64).expect("length of records");
 
@@ -125,30 +249,52 @@ fn write_metadata(wtr: &mut BufWriter<File>, ups : &[Update]) {
     wtr.write_u32::<BigEndian>(max_ts).expect("maximum timestamp");
 }
 
-fn write_reference(wtr: &mut Write, ref_ts: u32, ref_seq: u16, len: u16) {
+// `batch_max_ts` and `section_len` let a range query skip this whole batch
+// - via `max_ts < ts_start` and a single `SeekFrom::Current(section_len)` -
+// without decoding its records, even though varint deltas no longer bound
+// a batch's ts span the way the legacy fixed-width format did.
+fn write_reference<W: Write>(wtr: &mut W, ref_ts: u32, ref_seq: u16, len: u16, batch_max_ts: u32, section_len: u32) {
     let _ = wtr.write_u8(true as u8);
     let _ = wtr.write_u32::<BigEndian>(ref_ts);
     let _ = wtr.write_u16::<BigEndian>(ref_seq);
     let _ = wtr.write_u16::<BigEndian>(len);
+    let _ = wtr.write_u32::<BigEndian>(batch_max_ts);
+    let _ = wtr.write_u32::<BigEndian>(section_len);
 }
 
-fn write_main(mut wtr: &mut BufWriter<File>, ups : &[Update]) {
-    let _ = wtr.seek(SeekFrom::Start(MAIN_OFFSET));
+fn write_batch<W: Write>(wtr: &mut W, ref_ts: u32, ref_seq: u16, count: u16, batch_max_ts: u32, buf: &[u8], opts: &WriterOpts) {
+    if opts.compress_lvl > 0 {
+        let compressed = zstd::encode_all(buf, opts.compress_lvl).expect("zstd compress");
+        write_reference(wtr, ref_ts, ref_seq, count, batch_max_ts, compressed.len() as u32);
+        let _ = wtr.write(compressed.as_slice());
+    } else {
+        write_reference(wtr, ref_ts, ref_seq, count, batch_max_ts, buf.len() as u32);
+        let _ = wtr.write(buf);
+    }
+}
 
+/// Batches `ups` into one or more reference blocks and writes them at
+/// the writer's current position, without seeking. Shared by `write_main`
+/// (which starts a fresh file at `MAIN_OFFSET`) and `append` (which
+/// starts at the existing file's EOF).
+fn write_batches<W: Write>(wtr: &mut W, ups : &[Update], opts: &WriterOpts) {
     let mut buf : Vec<u8> = Vec::new();
 
     let mut ref_ts = ups[0].ts;
     let mut ref_seq = ups[0].seq;
+    let mut batch_max_ts = ups[0].ts;
     let mut count = 0;
 
     for elem in ups.iter() {
-        if count != 0 && elem.ts >= ref_ts + 65535 || elem.seq >= ref_seq + 255 {
-            write_reference(&mut wtr, ref_ts, ref_seq, count);
-            let _ = wtr.write(buf.as_slice());
+        // varint deltas have no range cap, so the only reason to start a
+        // new reference batch is the u16 `len` field in the header itself
+        if count != 0 && count >= 65535 {
+            write_batch(wtr, ref_ts, ref_seq, count, batch_max_ts, &buf, opts);
             buf.clear();
 
             ref_ts = elem.ts;
             ref_seq = elem.seq;
+            batch_max_ts = elem.ts;
             count = 0;
         }
 
@@ -156,41 +302,89 @@ fn write_main(mut wtr: &mut BufWriter<File>, ups : &[Update]) {
         let _ = buf.write_u8(false as u8);
         let _ = buf.write(serialized.as_slice());
 
+        if elem.ts > batch_max_ts {
+            batch_max_ts = elem.ts;
+        }
         count += 1;
     }
 
-    write_reference(&mut wtr, ref_ts, ref_seq, count);
-    wtr.write(buf.as_slice()).unwrap();
+    write_batch(wtr, ref_ts, ref_seq, count, batch_max_ts, &buf, opts);
 }
 
-pub fn encode(fname : &str, symbol : &str, ups : &[Update]) {
-    let mut wtr = file_writer(fname);
+fn write_main<W: Write + Seek>(wtr: &mut W, ups : &[Update], opts: &WriterOpts) {
+    let _ = wtr.seek(SeekFrom::Start(MAIN_OFFSET));
+    write_batches(wtr, ups, opts);
+}
 
-    write_magic_value(&mut wtr);
-    write_symbol(&mut wtr, symbol);
-    write_metadata(&mut wtr, ups);
-    write_main(&mut wtr, ups);
+/// Core encoder, generic over any seekable sink - an on-disk file, an
+/// in-memory `Cursor<Vec<u8>>`, or a DTF blob embedded inside a larger
+/// container format.
+pub fn encode_into<W: Write + Seek>(wtr: &mut W, symbol : &str, ups : &[Update], opts: &WriterOpts) {
+    let version = if opts.compress_lvl > 0 { FORMAT_VERSION_COMPRESSED } else { CURRENT_FORMAT_VERSION };
 
+    write_magic_value(wtr, version);
+    write_symbol(wtr, symbol);
+    write_metadata(wtr, ups);
+    write_main(wtr, ups, opts);
+}
+
+pub fn encode_opts(fname : &str, symbol : &str, ups : &[Update], opts: &WriterOpts) {
+    let mut wtr = file_writer(fname);
+    encode_into(&mut wtr, symbol, ups, opts);
     wtr.flush().expect("FAILURE TO FLUSH");
 }
 
-fn file_reader(fname: &str) -> BufReader<File> {
+pub fn encode(fname : &str, symbol : &str, ups : &[Update]) {
+    encode_opts(fname, symbol, ups, &WriterOpts::default())
+}
 
-    let file = File::open(fname).expect("OPENING FILE");
-    let mut rdr = BufReader::new(file);
+/// Validates `MAGIC_VALUE` and detects whether `SIGNATURE_TAIL` follows it,
+/// returning the byte shift to apply to every header offset after the
+/// magic value. Files written before the tail was introduced have the
+/// format version directly at `FORMAT_VERSION_OFFSET`; treating a missing
+/// tail as that legacy layout (shift 0) rather than a hard error is what
+/// lets those files keep decoding. A bad magic value either means this
+/// isn't a DTF file, or it's one that got mangled in transit - either way,
+/// fail fast with a typed error instead of silently decoding garbage.
+fn check_signature<R: Read + Seek>(rdr: &mut R) -> Result<u64, FileError> {
+    rdr.seek(SeekFrom::Start(0))?;
+
+    let mut magic = vec![0u8; MAGIC_VALUE.len()];
+    rdr.read_exact(&mut magic)?;
+    if magic != MAGIC_VALUE {
+        return Err(FileError::InvalidSignature);
+    }
 
-    // magic value
-    let _ = rdr.seek(SeekFrom::Start(0));
-    let mut buf = vec![0u8; 5];
-    let _ = rdr.read_exact(&mut buf);
-    if buf != MAGIC_VALUE {
-        panic!("MAGIC VALUE INCORRECT");
+    let mut tail = vec![0u8; SIGNATURE_TAIL.len()];
+    rdr.read_exact(&mut tail)?;
+
+    if tail == SIGNATURE_TAIL {
+        Ok(SIGNATURE_TAIL.len() as u64)
+    } else {
+        Ok(0)
     }
+}
+
+fn check_magic<R: Read + Seek>(rdr: &mut R) -> u64 {
+    check_signature(rdr).expect("invalid DTF file signature")
+}
 
-    rdr 
+fn file_reader(fname: &str) -> Result<(BufReader<File>, u64), FileError> {
+    let file = File::open(fname)?;
+    let mut rdr = BufReader::new(file);
+
+    let shift = check_signature(&mut rdr)?;
+
+    Ok((rdr, shift))
 }
-fn read_symbol(rdr : &mut BufReader<File>) -> String {
-    rdr.seek(SeekFrom::Start(SYMBOL_OFFSET));
+
+fn read_version<R: Read + Seek>(rdr : &mut R, shift: u64) -> u8 {
+    let _ = rdr.seek(SeekFrom::Start(FORMAT_VERSION_OFFSET + shift));
+    rdr.read_u8().expect("format version")
+}
+
+fn read_symbol<R: Read + Seek>(rdr : &mut R, shift: u64) -> String {
+    let _ = rdr.seek(SeekFrom::Start(SYMBOL_OFFSET + shift));
 
     let mut buffer = [0; SYMBOL_LEN];
     let _ = rdr.read_exact(&mut buffer);
@@ -199,39 +393,59 @@ fn read_symbol(rdr : &mut BufReader<File>) -> String {
     symbol
 }
 
-fn read_len(rdr : &mut BufReader<File>) -> u64 {
-    rdr.seek(SeekFrom::Start(LEN_OFFSET));
+fn read_len<R: Read + Seek>(rdr : &mut R, shift: u64) -> u64 {
+    let _ = rdr.seek(SeekFrom::Start(LEN_OFFSET + shift));
     rdr.read_u64::<BigEndian>().expect("length of records")
 }
 
-fn read_min_ts(mut rdr: &mut BufReader<File>) -> u32 {
-    read_first(&mut rdr).ts
-}
-
-fn read_max_ts(rdr : &mut BufReader<File>) -> u32 {
-    rdr.seek(SeekFrom::Start(MAX_TS_OFFSET));
+fn read_max_ts<R: Read + Seek>(rdr : &mut R, shift: u64) -> u32 {
+    let _ = rdr.seek(SeekFrom::Start(MAX_TS_OFFSET + shift));
     rdr.read_u32::<BigEndian>().expect("maximum timestamp")
 }
 
-fn read_one_batch(rdr: &mut BufReader<File>) -> Vec<Update> {
-    let is_ref = rdr.read_u8().expect("is_ref") == 0x00000001;
-    let mut ref_ts = 0;
-    let mut ref_seq = 0;
-    let mut how_many = 0;
-    let mut v : Vec<Update> = Vec::new();
+// is_record flag (1 byte) + dts/dseq/is_trade/is_bid/price/size (ITEM_OFFSET bytes)
+static RECORD_SIZE : u64 = 1 + ITEM_OFFSET;
 
-    if is_ref {
-        ref_ts = rdr.read_u32::<BigEndian>().unwrap();
-        ref_seq = rdr.read_u16::<BigEndian>().unwrap();
-        how_many = rdr.read_u16::<BigEndian>().unwrap();
-        println!("WILL READ: COUNT {}", how_many);
+// `batch_max_ts`/`section_len` are only present from FORMAT_VERSION_VARINT
+// onward; the legacy FORMAT_VERSION_FIXED layout predates both fields.
+fn read_reference_header<R: Read>(rdr : &mut R, version : u8) -> Option<(u32, u16, u16, Option<u32>, Option<u32>)> {
+    let is_ref = match rdr.read_u8() {
+        Ok(b) => b == 0x00000001,
+        Err(_) => return None,
+    };
+
+    if !is_ref {
+        return None;
     }
 
+    let ref_ts = rdr.read_u32::<BigEndian>().unwrap();
+    let ref_seq = rdr.read_u16::<BigEndian>().unwrap();
+    let how_many = rdr.read_u16::<BigEndian>().unwrap();
+
+    let (batch_max_ts, section_len) = if version == FORMAT_VERSION_FIXED {
+        (None, None)
+    } else {
+        let batch_max_ts = rdr.read_u32::<BigEndian>().unwrap();
+        let section_len = rdr.read_u32::<BigEndian>().unwrap();
+        (Some(batch_max_ts), Some(section_len))
+    };
+
+    Some((ref_ts, ref_seq, how_many, batch_max_ts, section_len))
+}
+
+fn read_batch_records<R: Read>(rdr : &mut R, ref_ts : u32, ref_seq : u16, how_many : u16, version : u8) -> Vec<Update> {
+    let mut v : Vec<Update> = Vec::new();
+
     for _i in 0..how_many {
         assert_eq!(rdr.read_u8().expect("is_ref"), 0x00000000);
+        let (dts, dseq) = if version == FORMAT_VERSION_FIXED {
+            (rdr.read_u16::<BigEndian>().expect("ts") as u64, rdr.read_u8().expect("seq") as u64)
+        } else {
+            (read_varint(rdr), read_varint(rdr))
+        };
         let current_update = Update {
-            ts: rdr.read_u16::<BigEndian>().expect("ts") as u32 + ref_ts,
-            seq: rdr.read_u8().expect("seq") as u16 + ref_seq,
+            ts: dts as u32 + ref_ts,
+            seq: dseq as u16 + ref_seq,
             is_trade: rdr.read_u8().expect("is_trade") == 0x00000001,
             is_bid: rdr.read_u8().expect("is_bid") == 0x00000001,
             price: rdr.read_f32::<BigEndian>().expect("price"),
@@ -243,59 +457,406 @@ fn read_one_batch(rdr: &mut BufReader<File>) -> Vec<Update> {
     v
 }
 
-fn read_first_batch(mut rdr: &mut BufReader<File>) -> Vec<Update> {
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-    read_one_batch(&mut rdr)
+/// Reads a batch's records, transparently inflating them first if the
+/// file is `FORMAT_VERSION_COMPRESSED` (in which case `section_len` is the
+/// compressed byte count rather than the raw length of the records).
+fn read_batch<R: Read>(rdr: &mut R, ref_ts: u32, ref_seq: u16, how_many: u16, version: u8, section_len: Option<u32>) -> Vec<Update> {
+    if version == FORMAT_VERSION_COMPRESSED {
+        let clen = section_len.expect("compressed batch missing section length") as usize;
+        let mut compressed = vec![0u8; clen];
+        rdr.read_exact(&mut compressed).expect("reading compressed batch");
+        let decompressed = zstd::decode_all(compressed.as_slice()).expect("zstd decompress");
+        let mut cursor = Cursor::new(decompressed);
+        read_batch_records(&mut cursor, ref_ts, ref_seq, how_many, version)
+    } else {
+        read_batch_records(rdr, ref_ts, ref_seq, how_many, version)
+    }
+}
+
+fn read_one_batch<R: Read>(rdr: &mut R, version : u8) -> Vec<Update> {
+    match read_reference_header(rdr, version) {
+        Some((ref_ts, ref_seq, how_many, _batch_max_ts, section_len)) => read_batch(rdr, ref_ts, ref_seq, how_many, version, section_len),
+        None => Vec::new(),
+    }
 }
 
-fn read_first(mut rdr: &mut BufReader<File>) -> Update {
+fn read_first<R: Read + Seek>(mut rdr: &mut R, version : u8) -> Update {
     rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-    let batch = read_one_batch(&mut rdr);
+    let batch = read_one_batch(&mut rdr, version);
     batch[0].clone()
 }
 
-pub fn decode(fname: &str) -> Vec<Update> {
+/// Core decoder, generic over any seekable source - an on-disk file, an
+/// in-memory `Cursor<Vec<u8>>`, or a DTF blob embedded inside a larger
+/// container format.
+pub fn decode_from<R: Read + Seek>(rdr: &mut R) -> Vec<Update> {
     let mut v : Vec<Update> = Vec::new();
-    let mut rdr = file_reader(fname);
-    let _symbol = read_symbol(&mut rdr); 
-    let _nums = read_len(&mut rdr);
-    let _max_ts = read_max_ts(&mut rdr);
+    let shift = check_magic(rdr);
+    let version = read_version(rdr, shift);
+    let _symbol = read_symbol(rdr, shift);
+    let _nums = read_len(rdr, shift);
+    let _max_ts = read_max_ts(rdr, shift);
 
     rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
 
     while let Ok(is_ref) = rdr.read_u8() {
         if is_ref == 0x00000001 {
             rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-            v.extend(read_one_batch(&mut rdr));
+            v.extend(read_one_batch(rdr, version));
         }
     }
 
     v
 }
 
-//TODO:
+pub fn decode(fname: &str) -> Vec<Update> {
+    let (mut rdr, _shift) = file_reader(fname).expect("OPENING FILE FOR DECODE");
+    decode_from(&mut rdr)
+}
+
+/// Iterates over the batches of a DTF source without loading the whole
+/// thing into memory. Seeks to `MAIN_OFFSET` once on open, then decodes
+/// a reference batch at a time, yielding its records one by one and
+/// refilling from the next batch when the current one is exhausted.
+/// Generic over any `Read + Seek` source, so it works equally well over
+/// a file, an in-memory `Cursor<Vec<u8>>`, or a DTF blob embedded in a
+/// larger container.
+pub struct DTFReader<R> {
+    rdr: R,
+    version: u8,
+    buf: Vec<Update>,
+}
+
+impl DTFReader<BufReader<File>> {
+    pub fn open(fname: &str) -> Result<DTFReader<BufReader<File>>, FileError> {
+        let (rdr, _shift) = file_reader(fname)?;
+        DTFReader::from_reader(rdr)
+    }
+}
+
+impl<R: Read + Seek> DTFReader<R> {
+    pub fn from_reader(mut rdr: R) -> Result<DTFReader<R>, FileError> {
+        let shift = check_signature(&mut rdr)?;
+        let version = read_version(&mut rdr, shift);
+        rdr.seek(SeekFrom::Start(MAIN_OFFSET))?;
+
+        Ok(DTFReader {
+            rdr: rdr,
+            version: version,
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl<R: Read> Iterator for DTFReader<R> {
+    type Item = Update;
+
+    fn next(&mut self) -> Option<Update> {
+        if self.buf.is_empty() {
+            match self.rdr.read_u8() {
+                Ok(is_ref) if is_ref == 0x00000001 => {
+                    let mut batch = read_batch_after_flag(&mut self.rdr, self.version);
+                    batch.reverse();
+                    self.buf = batch;
+                }
+                _ => return None,
+            }
+        }
+
+        self.buf.pop()
+    }
+}
+
+/// Reads the rest of a reference batch whose leading `is_ref` flag byte
+/// has already been consumed. `R: Read` (not `Read + Seek`) so this also
+/// works over sources, like a plain socket, that can't roll back a byte.
+fn read_batch_after_flag<R: Read>(rdr: &mut R, version : u8) -> Vec<Update> {
+    let ref_ts = rdr.read_u32::<BigEndian>().unwrap();
+    let ref_seq = rdr.read_u16::<BigEndian>().unwrap();
+    let how_many = rdr.read_u16::<BigEndian>().unwrap();
+
+    let section_len = if version == FORMAT_VERSION_FIXED {
+        None
+    } else {
+        let _batch_max_ts = rdr.read_u32::<BigEndian>().unwrap();
+        Some(rdr.read_u32::<BigEndian>().unwrap())
+    };
+
+    read_batch(rdr, ref_ts, ref_seq, how_many, version, section_len)
+}
+
+/// Decodes only the updates with `ts_start <= ts <= ts_end`, using the
+/// reference-batch layout to skip whole batches that fall outside the
+/// window without deserializing their records. From FORMAT_VERSION_VARINT
+/// onward every reference header carries the batch's own `max_ts` and the
+/// byte length of its records section, so a batch entirely below
+/// `ts_start` is skipped with a single `SeekFrom::Current` regardless of
+/// how wide its deltas are; the legacy fixed-width format predates both
+/// fields, so there a batch with reference timestamp `ref_ts` is assumed
+/// to span `[ref_ts, ref_ts + 65535]` (the `dts` u16's range) instead.
+/// Batches aren't guaranteed to appear in increasing `ts` order - `merge`
+/// interleaves cross-source batches by `seq`, not `ts` - so a batch
+/// starting after `ts_end` does NOT mean every later batch will too;
+/// as in the before-window case, there is no early exit, only a skip of
+/// this one batch.
+pub fn decode_range_from<R: Read + Seek>(rdr: &mut R, ts_start: u32, ts_end: u32) -> Vec<Update> {
+    let mut v : Vec<Update> = Vec::new();
+    let shift = check_magic(rdr);
+    let version = read_version(rdr, shift);
+    let _symbol = read_symbol(rdr, shift);
+    let _nums = read_len(rdr, shift);
+    let _max_ts = read_max_ts(rdr, shift);
+
+    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+
+    while let Some((ref_ts, ref_seq, how_many, batch_max_ts, section_len)) = read_reference_header(rdr, version) {
+        let outside_window = match batch_max_ts {
+            Some(max_ts) => max_ts < ts_start || ref_ts > ts_end,
+            None => (ref_ts as u64) + 65535 < ts_start as u64 || ref_ts > ts_end,
+        };
+
+        if outside_window {
+            let skip_bytes = match section_len {
+                Some(n) => n as i64,
+                None => how_many as i64 * RECORD_SIZE as i64,
+            };
+            let _ = rdr.seek(SeekFrom::Current(skip_bytes));
+            continue;
+        }
+
+        let batch = read_batch(rdr, ref_ts, ref_seq, how_many, version, section_len);
+        v.extend(batch.into_iter().filter(|up| up.ts >= ts_start && up.ts <= ts_end));
+    }
+
+    v
+}
+
+pub fn decode_range(fname: &str, ts_start: u32, ts_end: u32) -> Vec<Update> {
+    let (mut rdr, _shift) = file_reader(fname).expect("OPENING FILE FOR DECODE_RANGE");
+    decode_range_from(&mut rdr, ts_start, ts_end)
+}
+
+/// Appends `ups` to an existing DTF file as additional reference batches,
+/// without rewriting the records already on disk. Requires `ups` to sort
+/// entirely after the file's current max ts; appending into the middle
+/// of a file isn't supported. Only `FORMAT_VERSION_VARINT` files can be
+/// appended to, since the appended batches are always written
+/// uncompressed in that format.
 pub fn append(fname: &str, ups : &mut Vec<Update>) {
-    let new_max = {
-        let mut rdr = file_reader(fname);
-        let _symbol = read_symbol(&mut rdr);
+    let (version, old_len, old_max_ts) = {
+        let (mut rdr, shift) = file_reader(fname).expect("OPENING FILE FOR APPEND");
+        let version = read_version(&mut rdr, shift);
+        let old_len = read_len(&mut rdr, shift);
+        let old_max_ts = read_max_ts(&mut rdr, shift);
+        (version, old_len, old_max_ts)
+    };
+
+    if version != FORMAT_VERSION_VARINT {
+        panic!("append is only supported for FORMAT_VERSION_VARINT files");
+    }
+
+    ups.sort();
+    // `sort` orders by seq, so the lowest ts isn't necessarily ups[0]'s
+    let new_min = ups.iter().map(|up| up.ts).min().unwrap();
+    let new_max = get_max_ts(ups);
+
+    if new_min <= old_max_ts {
+        panic!("Cannot append data at or before the file's current max ts ({})", old_max_ts);
+    }
+
+    let file = OpenOptions::new().write(true).open(fname).expect("OPENING FILE FOR APPEND");
+    let mut wtr = BufWriter::new(file);
 
-        let max_ts = read_max_ts(&mut rdr);
-        let max_ts = read_min_ts(&mut rdr);
+    wtr.seek(SeekFrom::End(0)).expect("SEEKING TO EOF");
+    write_batches(&mut wtr, ups, &WriterOpts::default());
 
-        ups.sort();
-        let new_min = ups[0].ts;
-        let new_max = ups[ups.len()-1].ts;
+    // `version == FORMAT_VERSION_VARINT` above guarantees this file was
+    // written with the current (tail-present) header layout.
+    let current_shift = SIGNATURE_TAIL.len() as u64;
 
-        if new_min <= max_ts {
-            panic!("Cannot append data!(not implemented)");
+    let total_len = old_len + ups.len() as u64;
+    wtr.seek(SeekFrom::Start(LEN_OFFSET + current_shift)).expect("SEEKING LEN_OFFSET");
+    wtr.write_u64::<BigEndian>(total_len).expect("writing updated length");
+
+    if new_max > old_max_ts {
+        wtr.seek(SeekFrom::Start(MAX_TS_OFFSET + current_shift)).expect("SEEKING MAX_TS_OFFSET");
+        wtr.write_u32::<BigEndian>(new_max).expect("writing updated max ts");
+    }
+
+    wtr.flush().expect("FAILURE TO FLUSH");
+}
+
+/// Background writer for `merge`: owns the output `BufWriter` on its own
+/// thread and writes whatever serialized reference-block buffers arrive
+/// over a bounded channel, so the merge thread's batching/serialization
+/// overlaps with the disk writes instead of blocking on them.
+struct BackgroundWriter {
+    tx: SyncSender<Vec<u8>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    fn spawn(fname: &str, symbol: String) -> BackgroundWriter {
+        let (tx, rx) = sync_channel::<Vec<u8>>(64);
+        let fname = fname.to_owned();
+
+        let handle = thread::spawn(move || {
+            let mut wtr = file_writer(&fname);
+            write_magic_value(&mut wtr, CURRENT_FORMAT_VERSION);
+            write_symbol(&mut wtr, &symbol);
+            // placeholder length/max_ts, patched in place by `merge` once
+            // the full output has actually been written
+            wtr.write_u64::<BigEndian>(0).expect("placeholder length");
+            wtr.write_u32::<BigEndian>(0).expect("placeholder max ts");
+
+            // write_main starts the records section at MAIN_OFFSET; match
+            // that here so `merge`'s output is readable
+            // by the same decoders, instead of writing records right after
+            // the placeholder metadata.
+            wtr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING MAIN_OFFSET");
+
+            for buf in rx.iter() {
+                let _ = wtr.write(&buf);
+            }
+
+            wtr.flush().expect("FAILURE TO FLUSH");
+        });
+
+        BackgroundWriter { tx: tx, handle: Some(handle) }
+    }
+
+    fn send(&self, buf: Vec<u8>) {
+        self.tx.send(buf).expect("background writer thread died");
+    }
+
+    fn finish(mut self) {
+        drop(self.tx);
+        if let Some(handle) = self.handle.take() {
+            handle.join().expect("background writer thread panicked");
         }
-        new_max
+    }
+}
+
+/// A streaming input to `merge`: a lazy per-file decoder paired with the
+/// index of the file it came from, so the heap's tie-break between equal
+/// `seq`s is deterministic (earlier input wins) instead of arbitrary.
+struct MergeSource {
+    reader: DTFReader<BufReader<File>>,
+    index: usize,
+}
+
+/// K-way merges `inputs` into `out`, one globally `seq`-ordered DTF file.
+/// Each input is streamed lazily through `DTFReader` rather than loaded
+/// whole, with a `BinaryHeap` (wrapped in `Reverse` for min-heap order)
+/// holding the current front `Update` of every still-live input; the
+/// smallest is popped, appended to the output batch, and its source is
+/// refilled. The symbol is taken from the first input. Writing to disk
+/// happens on a `BackgroundWriter` thread so it overlaps with batching
+/// the next reference block on this thread.
+///
+/// The output is ordered by `seq`, not `ts` - the whole point of merging
+/// per-exchange files is combining independent `seq` spaces, and those
+/// venues' clocks don't agree on `ts` order. `Update::serialize` stores
+/// `ts` as a *delta* from the batch's reference ts, so popping a record
+/// whose `ts` is lower than the current reference would underflow that
+/// subtraction; whenever that happens the current batch is closed early
+/// and a fresh one is started with that record as its new reference,
+/// same as the existing 65535-count rollover below.
+pub fn merge(inputs: &[&str], out: &str) {
+    let symbol = {
+        let (mut rdr, shift) = file_reader(inputs[0]).expect("OPENING FIRST INPUT FOR SYMBOL");
+        read_symbol(&mut rdr, shift)
     };
+
+    let mut sources : Vec<MergeSource> = inputs.iter().enumerate().map(|(index, fname)| {
+        MergeSource {
+            reader: DTFReader::open(fname).expect("OPENING INPUT FOR MERGE"),
+            index: index,
+        }
+    }).collect();
+
+    let mut heap : BinaryHeap<Reverse<(Update, usize)>> = BinaryHeap::new();
+    for source in sources.iter_mut() {
+        if let Some(up) = source.reader.next() {
+            heap.push(Reverse((up, source.index)));
+        }
+    }
+
+    let opts = WriterOpts::default();
+    let writer = BackgroundWriter::spawn(out, symbol);
+
+    let mut ref_ts = 0;
+    let mut ref_seq = 0;
+    let mut count : u16 = 0;
+    let mut batch_max_ts : u32 = 0;
+    let mut record_buf : Vec<u8> = Vec::new();
+    let mut started = false;
+    let mut total : u64 = 0;
+    let mut max_ts : u32 = 0;
+
+    while let Some(Reverse((up, index))) = heap.pop() {
+        // also roll over when `up.ts` precedes the current reference ts,
+        // which happens whenever the heap's next-by-seq record comes from
+        // a venue running behind another's clock - see doc comment above
+        if !started || count >= 65535 || up.ts < ref_ts {
+            if started {
+                let mut block = Vec::new();
+                write_batch(&mut block, ref_ts, ref_seq, count, batch_max_ts, &record_buf, &opts);
+                writer.send(block);
+            }
+
+            ref_ts = up.ts;
+            ref_seq = up.seq;
+            batch_max_ts = up.ts;
+            count = 0;
+            record_buf.clear();
+            started = true;
+        }
+
+        let serialized = up.serialize(ref_ts, ref_seq);
+        let _ = record_buf.write_u8(false as u8);
+        let _ = record_buf.write(serialized.as_slice());
+        count += 1;
+
+        if up.ts > batch_max_ts {
+            batch_max_ts = up.ts;
+        }
+        if up.ts > max_ts {
+            max_ts = up.ts;
+        }
+        total += 1;
+
+        if let Some(next) = sources[index].reader.next() {
+            heap.push(Reverse((next, index)));
+        }
+    }
+
+    if started {
+        let mut block = Vec::new();
+        write_batch(&mut block, ref_ts, ref_seq, count, batch_max_ts, &record_buf, &opts);
+        writer.send(block);
+    }
+
+    writer.finish();
+
+    let file = OpenOptions::new().write(true).open(out).expect("OPENING MERGED FILE TO PATCH METADATA");
+    let mut wtr = BufWriter::new(file);
+
+    // BackgroundWriter::spawn always writes the current (tail-present) header.
+    let current_shift = SIGNATURE_TAIL.len() as u64;
+
+    wtr.seek(SeekFrom::Start(LEN_OFFSET + current_shift)).expect("SEEKING LEN_OFFSET");
+    wtr.write_u64::<BigEndian>(total).expect("writing merged length");
+
+    wtr.seek(SeekFrom::Start(MAX_TS_OFFSET + current_shift)).expect("SEEKING MAX_TS_OFFSET");
+    wtr.write_u32::<BigEndian>(max_ts).expect("writing merged max ts");
+
+    wtr.flush().expect("FAILURE TO FLUSH");
 }
 
 
 #[cfg(test)]
-fn init () -> Vec<Update> {
+fn init_at (fname : &str) -> Vec<Update> {
     let mut ts : Vec<Update> = vec![];
     let t = Update {
         ts: 100,
@@ -327,8 +888,6 @@ fn init () -> Vec<Update> {
 
     ts.sort();
 
-
-    let fname = "test.bin";
     let symbol = "NEO_BTC";
 
     encode(fname, symbol, &mut ts);
@@ -336,6 +895,11 @@ fn init () -> Vec<Update> {
     ts
 }
 
+#[cfg(test)]
+fn init () -> Vec<Update> {
+    init_at("test.bin")
+}
+
 #[test]
 fn should_encode_and_decode_file() {
     let ts = init();
@@ -348,8 +912,8 @@ fn should_encode_and_decode_file() {
 fn should_return_correct_symbol() {
     init();
     let fname = "test.bin";
-    let mut rdr = file_reader(fname);
-    let sym = read_symbol(&mut rdr);
+    let (mut rdr, shift) = file_reader(fname).unwrap();
+    let sym = read_symbol(&mut rdr, shift);
     assert_eq!(sym, "NEO_BTC  ");
 }
 
@@ -357,8 +921,9 @@ fn should_return_correct_symbol() {
 fn should_return_first_record() {
     let vs = init();
     let fname = "test.bin";
-    let mut rdr = file_reader(fname);
-    let v = read_first(&mut rdr);
+    let (mut rdr, shift) = file_reader(fname).unwrap();
+    let version = read_version(&mut rdr, shift);
+    let v = read_first(&mut rdr, version);
     assert_eq!(vs[0], v);
 }
 
@@ -366,8 +931,8 @@ fn should_return_first_record() {
 fn should_return_correct_num_of_items() {
     let vs = init();
     let fname = "test.bin";
-    let mut rdr = file_reader(fname);
-    let len = read_len(&mut rdr);
+    let (mut rdr, shift) = file_reader(fname).unwrap();
+    let len = read_len(&mut rdr, shift);
     assert_eq!(vs.len() as u64, len);
 }
 
@@ -375,11 +940,140 @@ fn should_return_correct_num_of_items() {
 fn should_return_max_ts() {
     let vs = init();
     let fname = "test.bin";
-    let mut rdr = file_reader(fname);
-    let max_ts = read_max_ts(&mut rdr);
+    let (mut rdr, shift) = file_reader(fname).unwrap();
+    let max_ts = read_max_ts(&mut rdr, shift);
     assert_eq!(max_ts, get_max_ts(&vs));
 }
 
+#[test]
+fn should_stream_decode_file() {
+    let vs = init();
+    let fname = "test.bin";
+    let streamed : Vec<Update> = DTFReader::open(fname).unwrap().collect();
+    assert_eq!(streamed, vs);
+}
+
+#[test]
+fn should_reject_bad_signature() {
+    let fname = "test_bad_signature.bin";
+    {
+        let mut wtr = BufWriter::new(File::create(fname).expect("CREATE"));
+        let _ = wtr.write(b"not a dtf file at all");
+        wtr.flush().expect("FLUSH");
+    }
+
+    match DTFReader::open(fname) {
+        Err(FileError::InvalidSignature) => (),
+        other => panic!("expected InvalidSignature, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn should_decode_legacy_file_without_signature_tail() {
+    // hand-roll the pre-tail header: magic, then version directly (no
+    // SIGNATURE_TAIL), symbol at offset 6, len at 15, max ts at 23
+    let fname = "test_legacy_no_tail.bin";
+    let t = Update {
+        ts: 42,
+        seq: 1,
+        is_trade: true,
+        is_bid: false,
+        price: 7.0,
+        size: 1.5,
+    };
+
+    {
+        let mut wtr = BufWriter::new(File::create(fname).expect("CREATE"));
+        let _ = wtr.write(MAGIC_VALUE);
+        let _ = wtr.write_u8(FORMAT_VERSION_FIXED);
+        let _ = wtr.write(b"NEO_BTC  ");
+        let _ = wtr.write_u64::<BigEndian>(1);
+        let _ = wtr.write_u32::<BigEndian>(t.ts);
+        wtr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+
+        let _ = wtr.write_u8(true as u8);
+        let _ = wtr.write_u32::<BigEndian>(t.ts);
+        let _ = wtr.write_u16::<BigEndian>(t.seq);
+        let _ = wtr.write_u16::<BigEndian>(1);
+
+        let _ = wtr.write_u8(false as u8);
+        let _ = wtr.write_u16::<BigEndian>(0); // dts
+        let _ = wtr.write_u8(0); // dseq
+        let _ = wtr.write_u8(t.is_trade as u8);
+        let _ = wtr.write_u8(t.is_bid as u8);
+        let _ = wtr.write_f32::<BigEndian>(t.price);
+        let _ = wtr.write_f32::<BigEndian>(t.size);
+
+        wtr.flush().expect("FLUSH");
+    }
+
+    assert_eq!(decode(fname), vec![t]);
+}
+
+#[test]
+fn should_decode_range() {
+    let vs = init();
+    let fname = "test.bin";
+    let decoded = decode_range(fname, 100, 101);
+    assert_eq!(decoded, vec![vs[0].clone(), vs[1].clone()]);
+}
+
+#[test]
+fn should_encode_and_decode_large_deltas_in_one_batch() {
+    // a ts gap this wide would have forced a new reference batch under
+    // the old fixed u16 dts; varint deltas keep it in a single batch
+    let t0 = Update {
+        ts: 100,
+        seq: 1,
+        is_trade: false,
+        is_bid: true,
+        price: 10.0,
+        size: 1.0,
+    };
+    let t1 = Update {
+        ts: 100 + 1_000_000,
+        seq: 1,
+        is_trade: true,
+        is_bid: false,
+        price: 20.0,
+        size: 2.0,
+    };
+    let ts = vec![t0, t1];
+
+    let fname = "test_varint.bin";
+    encode(fname, "NEO_BTC", &ts);
+    let decoded = decode(fname);
+    assert_eq!(decoded, ts);
+
+    // the first reference batch holds both records
+    let (mut rdr, shift) = file_reader(fname).unwrap();
+    let version = read_version(&mut rdr, shift);
+    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+    let (_, _, how_many, _, _) = read_reference_header(&mut rdr, version).unwrap();
+    assert_eq!(how_many, 2);
+}
+
+#[test]
+fn should_encode_and_decode_compressed_file() {
+    let ts = init();
+    let fname = "test_compressed.bin";
+    let opts = WriterOpts { compress_lvl: 3 };
+    encode_opts(fname, "NEO_BTC", &ts, &opts);
+    let decoded = decode(fname);
+    assert_eq!(decoded, ts);
+}
+
+#[test]
+fn should_encode_and_decode_in_memory_cursor() {
+    let ts = init();
+    let mut cur = Cursor::new(Vec::new());
+    encode_into(&mut cur, "NEO_BTC", &ts, &WriterOpts::default());
+
+    cur.set_position(0);
+    let decoded = decode_from(&mut cur);
+    assert_eq!(decoded, ts);
+}
+
 #[cfg(test)]
 fn init_real_data() -> Vec<Update> {
     let conf = conf::get_config();
@@ -402,5 +1096,111 @@ fn should_work_with_real_data() {
 
 #[test]
 fn should_append() {
+    // own fixture, not the shared "test.bin" - `append` mutates the file
+    // in place, which would race with the other tests' concurrent decodes
+    let fname = "test_append.bin";
+    let vs = init_at(fname);
+
+    let mut more = vec![Update {
+        ts: 2000000,
+        seq: 130,
+        is_trade: true,
+        is_bid: true,
+        price: 42.0,
+        size: 3.0,
+    }];
 
+    append(fname, &mut more);
+
+    let mut expected = vs.clone();
+    expected.extend(more);
+
+    let decoded = decode(fname);
+    assert_eq!(decoded, expected);
+}
+
+#[test]
+#[should_panic]
+fn should_reject_append_older_than_max_ts() {
+    // own fixture, not the shared "test.bin" - see should_append
+    let fname = "test_append_reject.bin";
+    init_at(fname);
+
+    let mut stale = vec![Update {
+        ts: 50,
+        seq: 1,
+        is_trade: false,
+        is_bid: false,
+        price: 1.0,
+        size: 1.0,
+    }];
+
+    append(fname, &mut stale);
+}
+
+#[test]
+fn should_merge_multiple_files() {
+    let a = vec![
+        Update { ts: 100, seq: 1, is_trade: false, is_bid: true, price: 1.0, size: 1.0 },
+        Update { ts: 300, seq: 3, is_trade: false, is_bid: true, price: 3.0, size: 1.0 },
+    ];
+    let b = vec![
+        Update { ts: 200, seq: 2, is_trade: true, is_bid: false, price: 2.0, size: 1.0 },
+        Update { ts: 400, seq: 4, is_trade: true, is_bid: false, price: 4.0, size: 1.0 },
+    ];
+
+    encode("test_merge_a.bin", "NEO_BTC", &a);
+    encode("test_merge_b.bin", "NEO_BTC", &b);
+
+    merge(&["test_merge_a.bin", "test_merge_b.bin"], "test_merge_out.bin");
+
+    let merged = decode("test_merge_out.bin");
+    assert_eq!(merged, vec![a[0].clone(), b[0].clone(), a[1].clone(), b[1].clone()]);
+}
+
+#[test]
+fn should_merge_files_with_nonmonotonic_ts_across_sources() {
+    // two venues whose seq spaces interleave but whose clocks disagree on
+    // ts order - merging by seq alone would otherwise underflow the ts
+    // delta when a later-seq record's ts precedes the batch's reference ts
+    let a = vec![
+        Update { ts: 500, seq: 1, is_trade: false, is_bid: true, price: 1.0, size: 1.0 },
+        Update { ts: 510, seq: 3, is_trade: false, is_bid: true, price: 3.0, size: 1.0 },
+    ];
+    let b = vec![
+        Update { ts: 100, seq: 2, is_trade: true, is_bid: false, price: 2.0, size: 1.0 },
+        Update { ts: 90, seq: 4, is_trade: true, is_bid: false, price: 4.0, size: 1.0 },
+    ];
+
+    encode("test_merge_nonmonotonic_a.bin", "NEO_BTC", &a);
+    encode("test_merge_nonmonotonic_b.bin", "NEO_BTC", &b);
+
+    merge(&["test_merge_nonmonotonic_a.bin", "test_merge_nonmonotonic_b.bin"], "test_merge_nonmonotonic_out.bin");
+
+    let merged = decode("test_merge_nonmonotonic_out.bin");
+    assert_eq!(merged, vec![a[0].clone(), b[0].clone(), a[1].clone(), b[1].clone()]);
+}
+
+#[test]
+fn should_decode_range_over_merged_file_with_out_of_order_batches() {
+    // same layout as should_merge_files_with_nonmonotonic_ts_across_sources:
+    // the first batch written (ts 500) starts after this query's ts_end, but
+    // a later batch (ts 90) is still in-window - decode_range must not treat
+    // the first batch's "starts after ts_end" as a signal to stop scanning
+    let a = vec![
+        Update { ts: 500, seq: 1, is_trade: false, is_bid: true, price: 1.0, size: 1.0 },
+        Update { ts: 510, seq: 3, is_trade: false, is_bid: true, price: 3.0, size: 1.0 },
+    ];
+    let b = vec![
+        Update { ts: 100, seq: 2, is_trade: true, is_bid: false, price: 2.0, size: 1.0 },
+        Update { ts: 90, seq: 4, is_trade: true, is_bid: false, price: 4.0, size: 1.0 },
+    ];
+
+    encode("test_merge_range_a.bin", "NEO_BTC", &a);
+    encode("test_merge_range_b.bin", "NEO_BTC", &b);
+
+    merge(&["test_merge_range_a.bin", "test_merge_range_b.bin"], "test_merge_range_out.bin");
+
+    let in_range = decode_range("test_merge_range_out.bin", 90, 100);
+    assert_eq!(in_range, vec![b[0].clone(), b[1].clone()]);
 }
\ No newline at end of file